@@ -0,0 +1,182 @@
+//! Future-based integration, enabled by the `async` feature.
+//!
+//! This keeps the synchronous `sleep`/`after`/`tick` API untouched: [Sleep] and the
+//! [Stream](../struct.Interval.html) impl on [Interval](../struct.Interval.html) are built
+//! on top of it by running the blocking wait on its own thread and waking the executor
+//! once it's done, the same way [Timer::after](../struct.Timer.html#method.after) already
+//! hands a blocking wait off to a thread.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use futures_core::Stream;
+use futures_util::stream::FusedStream;
+
+use crate::{Canceller, Interval, Timer};
+
+/// The state shared between a blocking wait running on its own thread and whichever
+/// executor is polling it.
+pub(crate) enum TickState {
+    /// No wait is currently running.
+    Idle,
+    /// A wait is running; holds the waker to notify once it finishes, if any has polled yet.
+    InFlight(Option<Waker>),
+    /// The wait finished with this result, not yet collected by a poll.
+    Ready(io::Result<()>),
+}
+
+fn spawn_wait<W>(state: Arc<Mutex<TickState>>, wait: W)
+where
+    W: FnOnce() -> io::Result<()> + Send + 'static,
+{
+    std::thread::Builder::new()
+        .spawn(move || {
+            let result = wait();
+            let waker = match std::mem::replace(&mut *state.lock().unwrap(), TickState::Ready(result)) {
+                TickState::InFlight(waker) => waker,
+                _ => None,
+            };
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        })
+        .expect("failed to spawn timer worker thread");
+}
+
+/// A [Future] that resolves once a duration elapses or its [Canceller] is used, for use
+/// with `async`/`await` and executors instead of blocking a thread on
+/// [Timer::sleep](../struct.Timer.html#method.sleep).
+///
+/// Dropping a `Sleep` before it resolves cancels the wait.
+pub struct Sleep {
+    state: Arc<Mutex<TickState>>,
+    canceller: Canceller,
+}
+
+impl Sleep {
+    /// Start sleeping for `duration`, returning the future and a [Canceller] that can be
+    /// used to cancel it from elsewhere.
+    pub fn new(duration: Duration) -> io::Result<(Self, Canceller)> {
+        let (mut timer, canceller) = Timer::new2()?;
+        let state = Arc::new(Mutex::new(TickState::Idle));
+        spawn_wait(state.clone(), move || timer.sleep(duration));
+        Ok((
+            Sleep {
+                state,
+                canceller: canceller.clone(),
+            },
+            canceller,
+        ))
+    }
+}
+
+impl Future for Sleep {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut guard = self.state.lock().unwrap();
+        match *guard {
+            TickState::Ready(_) => match std::mem::replace(&mut *guard, TickState::Ready(Ok(()))) {
+                TickState::Ready(result) => Poll::Ready(result),
+                _ => unreachable!(),
+            },
+            _ => {
+                *guard = TickState::InFlight(Some(cx.waker().clone()));
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        let _ = self.canceller.cancel();
+    }
+}
+
+impl Stream for Interval {
+    type Item = io::Result<()>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.terminated {
+            return Poll::Ready(None);
+        }
+        let mut guard = self.async_state.lock().unwrap();
+        match *guard {
+            TickState::Ready(_) => {
+                let result = match std::mem::replace(&mut *guard, TickState::Idle) {
+                    TickState::Ready(result) => result,
+                    _ => unreachable!(),
+                };
+                drop(guard);
+                if result.is_err() {
+                    self.terminated = true;
+                }
+                Poll::Ready(Some(result))
+            }
+            TickState::InFlight(ref mut waker_slot) => {
+                *waker_slot = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            TickState::Idle => {
+                *guard = TickState::InFlight(Some(cx.waker().clone()));
+                drop(guard);
+                self.elapsed_periods += 1;
+                let deadline = self.start + self.period * self.elapsed_periods;
+                let timer = self.timer.clone();
+                spawn_wait(self.async_state.clone(), move || {
+                    timer.lock().unwrap().sleep_until(deadline)
+                });
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl FusedStream for Interval {
+    fn is_terminated(&self) -> bool {
+        self.terminated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream::StreamExt;
+
+    #[test]
+    fn sleep_resolves_once_duration_elapses() {
+        let (sleep, _canceller) = Sleep::new(Duration::from_millis(50)).unwrap();
+        assert!(futures::executor::block_on(sleep).is_ok());
+    }
+
+    #[test]
+    fn cancelling_sleep_resolves_it_to_interrupted() {
+        let (sleep, canceller) = Sleep::new(Duration::from_secs(10)).unwrap();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            let _ = canceller.cancel();
+        });
+        let r = futures::executor::block_on(sleep);
+        assert_eq!(r.unwrap_err().kind(), io::ErrorKind::Interrupted);
+    }
+
+    #[test]
+    fn interval_stream_fuses_to_none_after_cancel() {
+        let (mut interval, canceller) = Timer::interval(Duration::from_millis(50)).unwrap();
+        futures::executor::block_on(async {
+            assert!(interval.next().await.unwrap().is_ok());
+            canceller.cancel().unwrap();
+            assert_eq!(
+                interval.next().await.unwrap().unwrap_err().kind(),
+                io::ErrorKind::Interrupted
+            );
+            assert!(interval.is_terminated());
+            assert!(interval.next().await.is_none());
+        });
+    }
+}
@@ -26,24 +26,51 @@
 
 extern crate mio;
 
+mod wheel;
+pub use wheel::{TimerWheel, WheelHandle};
+
+#[cfg(feature = "async")]
+mod r#async;
+#[cfg(feature = "async")]
+pub use r#async::Sleep;
+
 use std::io;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use mio::*;
 
+/// The authoritative state behind a [Timer]/[Canceller] pair's wake-up doorbell.
+///
+/// The mio `Ready` bits on the shared `Registration` are just a doorbell used to
+/// interrupt a blocking `poll()` call; they carry no information of their own; `sleep`
+/// always re-reads this instead, so clearing the doorbell can never lose a signal that
+/// [Canceller::cancel] or [Canceller::reset] already recorded here. `Cancelled` is
+/// terminal and is never overwritten by a later `reset`.
+#[derive(Clone, Copy)]
+enum Signal {
+    None,
+    Reset(Instant),
+    Cancelled,
+}
+
 /// A timer object that can be used to put the current thread to sleep
 /// or to start a callback after a given amount of time.
 pub struct Timer {
     poll: Poll,
     token: Token,
     _registration: Registration,
+    set_readiness: SetReadiness,
     events: Events,
+    signal: Arc<Mutex<Signal>>,
 }
 
-/// An object that allows cancelling the associated [Timer](struct.Timer.html).
+/// An object that allows cancelling, or rescheduling, the associated
+/// [Timer](struct.Timer.html).
 #[derive(Clone)]
 pub struct Canceller {
     set_readiness: SetReadiness,
+    signal: Arc<Mutex<Signal>>,
 }
 
 impl Timer {
@@ -54,39 +81,85 @@ impl Timer {
         let token = Token(0);
         let (registration, set_readiness) = Registration::new2();
         poll.register(&registration, token, Ready::readable(), PollOpt::edge())?;
+        let signal = Arc::new(Mutex::new(Signal::None));
 
         Ok((
             Timer {
                 poll,
                 token,
                 _registration: registration,
+                set_readiness: set_readiness.clone(),
                 events: Events::with_capacity(4),
+                signal: signal.clone(),
+            },
+            Canceller {
+                set_readiness,
+                signal,
             },
-            Canceller { set_readiness },
         ))
     }
 
-    /// Put the current thread to sleep until the given time has
-    /// elapsed or the timer is cancelled.
+    /// Put the current thread to sleep until the given time (or, after a reset, the new
+    /// deadline) has elapsed or the timer is cancelled.
     ///
     /// Returns:
     /// * Ok(()) if the given time has elapsed.
     /// * An [Error](https://docs.rust-lang.org/std/io/struct.Error.html)
-    /// of kind [ErrorKind::Interrupted](https://docs.rust-lang.org/std/io/enum.ErrorKind.html)
-    /// if the timer has been cancelled.
+    ///   of kind [ErrorKind::Interrupted](https://docs.rust-lang.org/std/io/enum.ErrorKind.html)
+    ///   if the timer has been cancelled.
     /// * Some other [Error](https://docs.rust-lang.org/std/io/struct.Error.html)
-    /// if something goes wrong.
+    ///   if something goes wrong.
+    ///
+    /// If [Canceller::reset](struct.Canceller.html#method.reset) is used while this call is
+    /// in flight, `sleep` recomputes the remaining time against the new deadline and keeps
+    /// waiting instead of returning, so a single `sleep` call can be pushed back any number
+    /// of times before it finally elapses or is cancelled.
     pub fn sleep(&mut self, duration: Duration) -> io::Result<()> {
-        self.poll.poll(&mut self.events, Some(duration))?;
-        for event in self.events.iter() {
-            if event.token() == self.token {
-                return Err(io::Error::new(
-                    io::ErrorKind::Interrupted,
-                    "timer cancelled",
-                ));
+        let mut remaining = duration;
+        loop {
+            let signal = *self.signal.lock().unwrap();
+            match signal {
+                Signal::Cancelled => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Interrupted,
+                        "timer cancelled",
+                    ));
+                }
+                Signal::Reset(deadline) => {
+                    *self.signal.lock().unwrap() = Signal::None;
+                    remaining = deadline.saturating_duration_since(Instant::now());
+                }
+                Signal::None => {}
             }
+
+            self.poll.poll(&mut self.events, Some(remaining))?;
+            let woke_via_doorbell = self.events.iter().any(|event| event.token() == self.token);
+            if woke_via_doorbell {
+                // Clear the doorbell so a later signal produces a fresh edge. Losing a
+                // concurrent update here is harmless: whatever `Canceller` wrote into
+                // `signal` is picked up by the state check at the top of the next
+                // iteration, independent of this readiness bit.
+                self.set_readiness.set_readiness(Ready::empty())?;
+                continue;
+            }
+            return Ok(());
         }
-        Ok(())
+    }
+
+    /// Put the current thread to sleep until the given deadline has passed
+    /// or the timer is cancelled.
+    ///
+    /// This mirrors [sleep](#method.sleep), but takes a wall-clock-independent
+    /// [Instant](https://doc.rust-lang.org/std/time/struct.Instant.html) instead of a
+    /// relative [Duration](https://doc.rust-lang.org/std/time/struct.Duration.html), so
+    /// callers can re-poll after being woken without extending the wait by re-deriving
+    /// a fresh relative duration. Returns `Ok(())` immediately if `deadline` is already
+    /// in the past.
+    ///
+    /// Returns the same results as [sleep](#method.sleep).
+    pub fn sleep_until(&mut self, deadline: Instant) -> io::Result<()> {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        self.sleep(remaining)
     }
 
     /// Run a callback on a new thread after a specified amount of time.
@@ -95,10 +168,10 @@ impl Timer {
     /// Otherwise, the callback is given:
     /// * Ok(()) if the amount of time has elapsed.
     /// * An [Error](https://docs.rust-lang.org/std/io/struct.Error.html)
-    /// of kind [ErrorKind::Interrupted](https://docs.rust-lang.org/std/io/enum.ErrorKind.html)
-    /// if the timer has been cancelled.
+    ///   of kind [ErrorKind::Interrupted](https://docs.rust-lang.org/std/io/enum.ErrorKind.html)
+    ///   if the timer has been cancelled.
     /// * Some other [Error](https://docs.rust-lang.org/std/io/struct.Error.html)
-    /// if something goes wrong.
+    ///   if something goes wrong.
     pub fn after<F>(wait: Duration, callback: F) -> io::Result<Canceller>
     where
         F: FnOnce(io::Result<()>),
@@ -110,11 +183,185 @@ impl Timer {
         })?;
         Ok(canceller)
     }
+
+    /// Run a callback on a new thread once the given deadline has passed.
+    /// The callback is not run if `at` returns an error.
+    ///
+    /// This mirrors [after](#method.after), but takes an absolute
+    /// [Instant](https://doc.rust-lang.org/std/time/struct.Instant.html) instead of a
+    /// relative [Duration](https://doc.rust-lang.org/std/time/struct.Duration.html).
+    ///
+    /// The callback is given the same results as [after](#method.after)'s callback.
+    pub fn at<F>(deadline: Instant, callback: F) -> io::Result<Canceller>
+    where
+        F: FnOnce(io::Result<()>),
+        F: Send + 'static,
+    {
+        let (mut timer, canceller) = Timer::new2()?;
+        std::thread::Builder::new().spawn(move || {
+            callback(timer.sleep_until(deadline));
+        })?;
+        Ok(canceller)
+    }
+
+    /// Run `op` on a new thread, racing it against a `duration` timeout.
+    ///
+    /// `op` is given a clone of the [Canceller](struct.Canceller.html) of the timer that
+    /// `with_timeout` waits on. A `Canceller` offers no way to ask whether it has been
+    /// cancelled, so `op` cannot use it to notice that the timeout has elapsed. Calling
+    /// [cancel](struct.Canceller.html#method.cancel) on it ends the timeout early and makes
+    /// `with_timeout` wait for `op`'s result; [reset](struct.Canceller.html#method.reset)
+    /// pushes the timeout back. Nothing stops `op` early: it is plain Rust code running on
+    /// its own thread, so `with_timeout` has no way to preempt it. Once `duration` elapses,
+    /// `with_timeout` returns without waiting for `op`'s thread to finish, which is left
+    /// running in the background.
+    ///
+    /// Returns:
+    /// * `Ok(T)` with `op`'s result, if `op` completed before `duration` elapsed.
+    /// * An [Error](https://docs.rust-lang.org/std/io/struct.Error.html)
+    ///   of kind [ErrorKind::TimedOut](https://docs.rust-lang.org/std/io/enum.ErrorKind.html)
+    ///   if `duration` elapsed before `op` completed.
+    /// * Some other [Error](https://docs.rust-lang.org/std/io/struct.Error.html)
+    ///   if something goes wrong, or if `op`'s thread panicked before reporting a result.
+    pub fn with_timeout<T, F>(duration: Duration, op: F) -> io::Result<T>
+    where
+        F: FnOnce(Canceller) -> T,
+        F: Send + 'static,
+        T: Send + 'static,
+    {
+        let (mut timer, canceller) = Timer::new2()?;
+        let op_canceller = canceller.clone();
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::Builder::new().spawn(move || {
+            let outcome = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                op(op_canceller.clone())
+            }));
+            match outcome {
+                Ok(result) => {
+                    let _ = tx.send(result);
+                    // Let the timer thread know `op` is done, so it stops waiting.
+                    let _ = op_canceller.cancel();
+                }
+                Err(payload) => {
+                    // Drop the sender before waking the timer thread, so its `recv`
+                    // reports the panic instead of blocking.
+                    drop(tx);
+                    let _ = op_canceller.cancel();
+                    std::panic::resume_unwind(payload);
+                }
+            }
+        })?;
+
+        match timer.sleep(duration) {
+            Ok(()) => {
+                // The timeout won the race. Mark the shared `Canceller` cancelled, so a
+                // later `reset` from `op` has no effect, and return without waiting for
+                // `op`'s thread.
+                let _ = canceller.cancel();
+                Err(io::Error::new(io::ErrorKind::TimedOut, "operation timed out"))
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                // Either `op`'s thread finished and cancelled us, having sent its result
+                // (or dropped the sender, if `op` panicked) first, so this does not
+                // block; or `op` cancelled its `Canceller` itself, and this waits for it.
+                rx.recv()
+                    .map_err(|_| io::Error::other("with_timeout: op thread panicked"))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Create an [Interval](struct.Interval.html) that fires once every `period`,
+    /// and its associated [Canceller](struct.Canceller.html).
+    pub fn interval(period: Duration) -> io::Result<(Interval, Canceller)> {
+        let (timer, canceller) = Timer::new2()?;
+        Ok((
+            Interval {
+                timer: Arc::new(Mutex::new(timer)),
+                start: Instant::now(),
+                period,
+                elapsed_periods: 0,
+                terminated: false,
+                #[cfg(feature = "async")]
+                async_state: Arc::new(Mutex::new(r#async::TickState::Idle)),
+            },
+            canceller,
+        ))
+    }
+}
+
+/// A timer that, once created with [Timer::interval](struct.Timer.html#method.interval),
+/// fires repeatedly at a fixed period until its [Canceller](struct.Canceller.html) is used.
+///
+/// Unlike sleeping for `period` again after every [tick](#method.tick), `Interval` tracks
+/// deadlines as `start + n * period`, so a slow caller or a spurious wakeup doesn't push
+/// later ticks further out: the schedule never drifts.
+pub struct Interval {
+    timer: Arc<Mutex<Timer>>,
+    start: Instant,
+    period: Duration,
+    elapsed_periods: u32,
+    terminated: bool,
+    #[cfg(feature = "async")]
+    async_state: Arc<Mutex<r#async::TickState>>,
+}
+
+impl Interval {
+    /// Put the current thread to sleep until the next tick of the interval.
+    ///
+    /// Returns:
+    /// * Ok(()) once `start + n * period` (for the `n`th call) has elapsed.
+    /// * An [Error](https://docs.rust-lang.org/std/io/struct.Error.html)
+    ///   of kind [ErrorKind::Interrupted](https://docs.rust-lang.org/std/io/enum.ErrorKind.html)
+    ///   if the interval has been cancelled. Once this happens the interval is stopped for
+    ///   good; further calls to `tick` will keep returning this error without sleeping again.
+    /// * Some other [Error](https://docs.rust-lang.org/std/io/struct.Error.html)
+    ///   if something goes wrong.
+    pub fn tick(&mut self) -> io::Result<()> {
+        if self.terminated {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "timer cancelled",
+            ));
+        }
+        self.elapsed_periods += 1;
+        let deadline = self.start + self.period * self.elapsed_periods;
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let result = self.timer.lock().unwrap().sleep(remaining);
+        if result.is_err() {
+            self.terminated = true;
+        }
+        result
+    }
 }
 
 impl Canceller {
     /// Cancel the associated [Timer](struct.Timer.html).
+    ///
+    /// This is terminal: once cancelled, a [reset](#method.reset) from elsewhere can no
+    /// longer bring the timer back.
     pub fn cancel(&self) -> io::Result<()> {
+        *self.signal.lock().unwrap() = Signal::Cancelled;
+        self.set_readiness.set_readiness(Ready::readable())
+    }
+
+    /// Reschedule the associated [Timer](struct.Timer.html) to fire `new` from now,
+    /// without cancelling it.
+    ///
+    /// If the timer is currently in a [sleep](struct.Timer.html#method.sleep) call, that
+    /// call wakes up, recomputes its remaining wait against the new deadline, and keeps
+    /// waiting rather than returning. This makes it possible to build debounce or
+    /// keep-alive primitives: reset the deadline every time new activity arrives, and the
+    /// timer only fires once activity has stopped for `new`.
+    ///
+    /// Has no effect if the timer has already been [cancelled](#method.cancel).
+    pub fn reset(&self, new: Duration) -> io::Result<()> {
+        {
+            let mut signal = self.signal.lock().unwrap();
+            if !matches!(*signal, Signal::Cancelled) {
+                *signal = Signal::Reset(Instant::now() + new);
+            }
+        }
         self.set_readiness.set_readiness(Ready::readable())
     }
 }
@@ -149,4 +396,101 @@ mod tests {
         let r = timer.sleep(Duration::from_secs(10));
         assert_eq!(r.unwrap_err().kind(), io::ErrorKind::Interrupted);
     }
+
+    #[test]
+    fn interval_ticks_repeatedly() {
+        let (mut interval, _canceller) = Timer::interval(Duration::from_millis(100)).unwrap();
+        assert!(interval.tick().is_ok());
+        assert!(interval.tick().is_ok());
+        assert!(interval.tick().is_ok());
+    }
+
+    #[test]
+    fn sleep_until_past_deadline_returns_immediately() {
+        let (mut timer, _) = Timer::new2().unwrap();
+        let r = timer.sleep_until(Instant::now() - Duration::from_secs(1));
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn sleep_until_future_deadline() {
+        let (mut timer, _) = Timer::new2().unwrap();
+        let r = timer.sleep_until(Instant::now() + Duration::from_millis(100));
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn interval_cancel_stops_it_for_good() {
+        let (mut interval, canceller) = Timer::interval(Duration::from_millis(100)).unwrap();
+        canceller.cancel().unwrap();
+        for _ in 0..3 {
+            let r = interval.tick();
+            assert_eq!(r.unwrap_err().kind(), io::ErrorKind::Interrupted);
+        }
+    }
+
+    #[test]
+    fn reset_pushes_back_a_sleep_in_flight() {
+        let (mut timer, canceller) = Timer::new2().unwrap();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            canceller.reset(Duration::from_millis(200)).unwrap();
+        });
+        let start = std::time::Instant::now();
+        let r = timer.sleep(Duration::from_millis(300));
+        assert!(r.is_ok());
+        assert!(start.elapsed() >= Duration::from_millis(350));
+    }
+
+    #[test]
+    fn cancel_after_reset_still_interrupts() {
+        let (mut timer, canceller) = Timer::new2().unwrap();
+        let reset_canceller = canceller.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            reset_canceller.reset(Duration::from_secs(10)).unwrap();
+            thread::sleep(Duration::from_millis(100));
+            reset_canceller.cancel().unwrap();
+        });
+        let r = timer.sleep(Duration::from_millis(200));
+        assert_eq!(r.unwrap_err().kind(), io::ErrorKind::Interrupted);
+    }
+
+    #[test]
+    fn cancel_racing_reset_is_never_lost() {
+        // Regardless of how close together a reset and a cancel land, the cancel must
+        // win: it is recorded in `signal` directly rather than inferred from which
+        // readiness bit happened to be observed.
+        for _ in 0..100 {
+            let (mut timer, canceller) = Timer::new2().unwrap();
+            let racer = canceller.clone();
+            thread::spawn(move || {
+                let _ = racer.reset(Duration::from_secs(10));
+                let _ = racer.cancel();
+            });
+            let r = timer.sleep(Duration::from_millis(50));
+            assert_eq!(r.unwrap_err().kind(), io::ErrorKind::Interrupted);
+        }
+    }
+
+    #[test]
+    fn with_timeout_returns_op_result_when_it_finishes_first() {
+        let r = Timer::with_timeout(Duration::from_secs(1), |_canceller| {
+            thread::sleep(Duration::from_millis(50));
+            42
+        });
+        assert_eq!(r.unwrap(), 42);
+    }
+
+    #[test]
+    fn with_timeout_times_out_when_op_is_too_slow() {
+        let start = std::time::Instant::now();
+        let r = Timer::with_timeout(Duration::from_millis(50), |_canceller| {
+            // `op` ignores its `Canceller` and nothing preempts it; `with_timeout` must
+            // still return promptly instead of waiting for it.
+            thread::sleep(Duration::from_secs(10));
+        });
+        assert_eq!(r.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
 }
@@ -0,0 +1,375 @@
+//! A scheduler that multiplexes many timers onto a single background thread,
+//! using a hierarchical timing wheel (the same approach as tokio's driver).
+//!
+//! [Timer::after](../struct.Timer.html#method.after) spawns a new OS thread per timer,
+//! which doesn't scale to large numbers of in-flight timers. [TimerWheel] instead owns a
+//! single background thread and a single [Timer], and buckets every registered callback
+//! into one of `LEVELS` levels of `SLOTS` slots each, where level `n` covers `SLOTS` times
+//! the span of level `n - 1`. Inserting, firing and cancelling a callback never scan every
+//! pending entry: the worker only ever looks at the slot(s) that are actually due,
+//! cascading entries down from higher levels into lower ones as time passes, and the
+//! earliest pending deadline and each entry's current slot are tracked incrementally.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{Canceller, Timer};
+
+const LEVELS: usize = 6;
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS; // 64
+const SLOT_MASK: u64 = (SLOTS as u64) - 1;
+/// The span of one level-0 slot, and the wheel's scheduling granularity.
+const TICK: Duration = Duration::from_millis(1);
+
+type EntryId = u64;
+
+struct Entry {
+    id: EntryId,
+    tick: u64,
+    callback: Box<dyn FnOnce() + Send>,
+}
+
+struct Levels {
+    slots: Vec<Vec<Vec<Entry>>>,
+    // Where each still-pending entry currently lives, so `remove` doesn't have to scan
+    // every slot.
+    index: HashMap<EntryId, (usize, usize)>,
+    // How many pending entries exist for each tick, so the earliest one can be found
+    // without scanning every slot. Cascading an entry between levels doesn't change its
+    // tick, so this only needs updating on insert/remove/fire.
+    pending_ticks: BTreeMap<u64, usize>,
+}
+
+impl Levels {
+    fn new() -> Self {
+        Levels {
+            slots: (0..LEVELS)
+                .map(|_| (0..SLOTS).map(|_| Vec::new()).collect())
+                .collect(),
+            index: HashMap::new(),
+            pending_ticks: BTreeMap::new(),
+        }
+    }
+
+    /// The lowest level (and its slot) whose granularity can still represent `tick`
+    /// relative to the wheel's current position `now_tick`.
+    fn locate(now_tick: u64, tick: u64) -> (usize, usize) {
+        let delta = tick.saturating_sub(now_tick);
+        for level in 0..LEVELS - 1 {
+            if delta >> ((level as u32 + 1) * SLOT_BITS) == 0 {
+                let slot = (tick >> (level as u32 * SLOT_BITS)) & SLOT_MASK;
+                return (level, slot as usize);
+            }
+        }
+        let slot = (tick >> ((LEVELS as u32 - 1) * SLOT_BITS)) & SLOT_MASK;
+        (LEVELS - 1, slot as usize)
+    }
+
+    fn bump_tick(&mut self, tick: u64) {
+        *self.pending_ticks.entry(tick).or_insert(0) += 1;
+    }
+
+    fn unbump_tick(&mut self, tick: u64) {
+        if let std::collections::btree_map::Entry::Occupied(mut occupied) =
+            self.pending_ticks.entry(tick)
+        {
+            *occupied.get_mut() -= 1;
+            if *occupied.get() == 0 {
+                occupied.remove();
+            }
+        }
+    }
+
+    /// Insert a freshly-registered entry: tracked in both `index` and `pending_ticks`.
+    ///
+    /// An entry due at or before `now_tick` (a sub-`TICK` wait, or one armed from a
+    /// callback already running at the due tick) would otherwise `locate` into the same
+    /// level-0 slot that tick just swept, and not be visited again until that slot index
+    /// wraps back around `SLOTS` ticks later. Clamp it to fire on the very next tick
+    /// instead, which is already this wheel's scheduling granularity for every entry.
+    ///
+    /// Returns the entry's actual tick after clamping, since the caller may need it to
+    /// tell whether this insertion became the new earliest pending tick.
+    fn insert(&mut self, now_tick: u64, mut entry: Entry) -> u64 {
+        entry.tick = entry.tick.max(now_tick + 1);
+        let tick = entry.tick;
+        let (level, slot) = Self::locate(now_tick, tick);
+        self.index.insert(entry.id, (level, slot));
+        self.bump_tick(tick);
+        self.slots[level][slot].push(entry);
+        tick
+    }
+
+    /// Re-bucket `entry` after a cascade: its tick hasn't changed, so `pending_ticks`
+    /// doesn't need updating, only `index` and its slot.
+    fn reinsert(&mut self, now_tick: u64, entry: Entry) {
+        let (level, slot) = Self::locate(now_tick, entry.tick);
+        self.index.insert(entry.id, (level, slot));
+        self.slots[level][slot].push(entry);
+    }
+
+    /// Cancel a still-pending entry in O(1) lookup plus O(entries in its slot) removal,
+    /// instead of scanning the whole wheel.
+    fn remove(&mut self, id: EntryId) {
+        if let Some((level, slot)) = self.index.remove(&id) {
+            let slot = &mut self.slots[level][slot];
+            if let Some(pos) = slot.iter().position(|e| e.id == id) {
+                let entry = slot.remove(pos);
+                self.unbump_tick(entry.tick);
+            }
+        }
+    }
+
+    /// Advance from `from_tick` (exclusive) to `to_tick` (inclusive), cascading entries
+    /// down from higher levels as their slot boundaries wrap, and return every entry
+    /// that is now due.
+    fn advance(&mut self, from_tick: u64, to_tick: u64) -> Vec<Entry> {
+        let mut due = Vec::new();
+        let mut tick = from_tick;
+        while tick < to_tick {
+            tick += 1;
+            for level in 1..LEVELS {
+                if tick & ((1u64 << (level as u32 * SLOT_BITS)) - 1) != 0 {
+                    break;
+                }
+                let slot = ((tick >> (level as u32 * SLOT_BITS)) & SLOT_MASK) as usize;
+                for entry in std::mem::take(&mut self.slots[level][slot]) {
+                    self.reinsert(tick, entry);
+                }
+            }
+            let slot0 = (tick & SLOT_MASK) as usize;
+            for entry in std::mem::take(&mut self.slots[0][slot0]) {
+                self.index.remove(&entry.id);
+                self.unbump_tick(entry.tick);
+                due.push(entry);
+            }
+        }
+        due
+    }
+
+    /// The tick of the earliest pending entry across every level, if any.
+    fn next_tick(&self) -> Option<u64> {
+        self.pending_ticks.keys().next().copied()
+    }
+}
+
+struct State {
+    start: Instant,
+    now_tick: u64,
+    levels: Levels,
+}
+
+impl State {
+    fn tick_for(&self, deadline: Instant) -> u64 {
+        let elapsed = deadline.saturating_duration_since(self.start);
+        // Round up so a callback never fires before its deadline.
+        let nanos = elapsed.as_nanos() + TICK.as_nanos() - 1;
+        (nanos / TICK.as_nanos()) as u64
+    }
+}
+
+/// A scheduler that multiplexes many [TimerWheel::after](struct.TimerWheel.html#method.after)
+/// callbacks onto a single background thread, instead of spawning one thread per timer
+/// like [Timer::after](../struct.Timer.html#method.after) does.
+pub struct TimerWheel {
+    state: Arc<Mutex<State>>,
+    canceller: Canceller,
+    next_id: AtomicU64,
+}
+
+/// A handle to a callback registered with [TimerWheel::after](struct.TimerWheel.html#method.after).
+///
+/// Dropping the handle does not cancel the callback; call [cancel](#method.cancel)
+/// explicitly to do that.
+pub struct WheelHandle {
+    id: EntryId,
+    state: Arc<Mutex<State>>,
+}
+
+impl WheelHandle {
+    /// Cancel the callback if it has not already fired.
+    pub fn cancel(&self) {
+        self.state.lock().unwrap().levels.remove(self.id);
+    }
+}
+
+impl TimerWheel {
+    /// Create a [TimerWheel] and spawn its background worker thread.
+    pub fn new() -> std::io::Result<Self> {
+        let (mut timer, canceller) = Timer::new2()?;
+        let state = Arc::new(Mutex::new(State {
+            start: Instant::now(),
+            now_tick: 0,
+            levels: Levels::new(),
+        }));
+
+        let worker_state = state.clone();
+        std::thread::Builder::new().spawn(move || loop {
+            let wait = {
+                let guard = worker_state.lock().unwrap();
+                match guard.levels.next_tick() {
+                    // `Levels::insert` guarantees every pending tick is > now_tick, so
+                    // this is always at least one full TICK: no busy-spinning on a
+                    // zero-length wait for an entry that's already due.
+                    Some(tick) => TICK * ((tick - guard.now_tick) as u32),
+                    // Nothing pending: wait a while, a fresh insertion will reset() us sooner.
+                    None => Duration::from_secs(60),
+                }
+            };
+            match timer.sleep(wait) {
+                Ok(()) => {
+                    let due = {
+                        let mut guard = worker_state.lock().unwrap();
+                        // Round the current time down (unlike `tick_for`), so an entry
+                        // due later within the running TICK is not fired early.
+                        let elapsed = Instant::now().saturating_duration_since(guard.start);
+                        let to_tick = (elapsed.as_nanos() / TICK.as_nanos()) as u64;
+                        let from_tick = guard.now_tick;
+                        guard.now_tick = to_tick;
+                        guard.levels.advance(from_tick, to_tick)
+                    };
+                    for entry in due {
+                        (entry.callback)();
+                    }
+                }
+                // Cancelled: the wheel has been dropped, stop the worker for good.
+                Err(_) => break,
+            }
+        })?;
+
+        Ok(TimerWheel {
+            state,
+            canceller,
+            next_id: AtomicU64::new(0),
+        })
+    }
+
+    /// Run `callback` on the wheel's background thread once `wait` has elapsed.
+    ///
+    /// Returns a [WheelHandle] that can be used to cancel the callback before it fires.
+    pub fn after<F>(&self, wait: Duration, callback: F) -> WheelHandle
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut guard = self.state.lock().unwrap();
+        let tick = guard.tick_for(Instant::now() + wait);
+        let now_tick = guard.now_tick;
+        let tick = guard.levels.insert(
+            now_tick,
+            Entry {
+                id,
+                tick,
+                callback: Box::new(callback),
+            },
+        );
+        let wakes_up_sooner = guard.levels.next_tick() == Some(tick);
+        drop(guard);
+        if wakes_up_sooner {
+            // The worker might be sleeping past this new deadline: wake it early.
+            let _ = self.canceller.reset(wait);
+        }
+        WheelHandle {
+            id,
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl Drop for TimerWheel {
+    fn drop(&mut self) {
+        let _ = self.canceller.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn fires_after_the_requested_duration() {
+        let wheel = TimerWheel::new().unwrap();
+        let (tx, rx) = mpsc::channel();
+        wheel.after(Duration::from_millis(50), move || {
+            tx.send(()).unwrap();
+        });
+        rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    }
+
+    #[test]
+    fn cancel_prevents_the_callback_from_firing() {
+        let wheel = TimerWheel::new().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let handle = wheel.after(Duration::from_millis(100), move || {
+            tx.send(()).unwrap();
+        });
+        handle.cancel();
+        assert!(rx.recv_timeout(Duration::from_millis(300)).is_err());
+    }
+
+    #[test]
+    fn many_timers_fire_in_scheduled_order() {
+        let wheel = TimerWheel::new().unwrap();
+        let (tx, rx) = mpsc::channel();
+        for i in 0..20u64 {
+            let tx = tx.clone();
+            wheel.after(Duration::from_millis((20 - i) * 5), move || {
+                tx.send(i).unwrap();
+            });
+        }
+        drop(tx);
+        let fired: Vec<u64> = rx.iter().collect();
+        assert_eq!(fired.len(), 20);
+        // Waits shrink as the id grows (100ms for id 0 down to 5ms for id 19), so they
+        // should fire in decreasing id order: 19, 18, ..., 0.
+        let expected: Vec<u64> = (0..20u64).rev().collect();
+        assert_eq!(fired, expected);
+    }
+
+    #[test]
+    fn sub_tick_timer_armed_while_running_fires_promptly() {
+        let wheel = TimerWheel::new().unwrap();
+
+        // Get the wheel's clock moving first, so the entry below isn't the first thing
+        // ever inserted into a fresh, all-zero wheel.
+        let (tx0, rx0) = mpsc::channel();
+        wheel.after(Duration::from_millis(5), move || tx0.send(()).unwrap());
+        rx0.recv_timeout(Duration::from_secs(1)).unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        wheel.after(Duration::from_micros(300), move || tx.send(()).unwrap());
+        // A due-now entry should land in a slot that's visited on the wheel's very next
+        // tick, not one that was already swept this tick and won't be revisited until
+        // the level-0 index wraps back around ~SLOTS ticks (tens of ms) later.
+        rx.recv_timeout(Duration::from_millis(20)).unwrap();
+    }
+
+    #[test]
+    fn chained_sub_tick_timers_do_not_accumulate_wraparound_delay() {
+        const CHAIN: u32 = 50;
+
+        fn schedule_next(wheel: Arc<TimerWheel>, tx: mpsc::Sender<()>, remaining: u32) {
+            if remaining == 0 {
+                tx.send(()).unwrap();
+                return;
+            }
+            let next_wheel = wheel.clone();
+            wheel.after(Duration::from_micros(300), move || {
+                schedule_next(next_wheel, tx, remaining - 1);
+            });
+        }
+
+        let wheel = Arc::new(TimerWheel::new().unwrap());
+        let (tx, rx) = mpsc::channel();
+        let start = Instant::now();
+        schedule_next(wheel, tx, CHAIN);
+        rx.recv_timeout(Duration::from_secs(2)).unwrap();
+        // Each hop only needs to clear the wheel's own tick granularity; if a hop were
+        // missing its slot it would instead cost ~SLOTS ticks (tens of ms) to recover.
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+}